@@ -0,0 +1,34 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Implementation of panics backed by system-specific unwinding.
+//!
+//! This is the crate that is intended to implement the bulk of the low-level
+//! details of the implementation of panics. This crate is not intended to
+//! provide a nice API, but rather the actual implementation.
+
+#![no_std]
+#![unstable(feature = "panic_unwind", issue = "32837")]
+#![feature(alloc)]
+#![feature(core_intrinsics)]
+#![feature(lang_items)]
+#![feature(raw)]
+#![feature(staged_api)]
+#![feature(thread_local)]
+#![feature(unwind_attributes)]
+
+extern crate alloc;
+
+#[cfg(target_env = "msvc")]
+#[path = "seh.rs"]
+mod imp;
+
+#[cfg(target_env = "msvc")]
+pub use imp::*;
@@ -19,7 +19,7 @@
 //! In a nutshell, what happens here is:
 //!
 //! 1. The `panic` function calls the standard Windows function
-//!    `_CxxThrowException` to throw a C++-like exception, triggering the
+//!    `RaiseException` with a Rust-specific exception code, triggering the
 //!    unwinding process.
 //! 2. All landing pads generated by the compiler use the personality function
 //!    `__CxxFrameHandler3`, a function in the CRT, and the unwinding code in
@@ -38,16 +38,24 @@
 //! Some specific differences from the gcc-based exception handling are:
 //!
 //! * Rust has no custom personality function, it is instead *always*
-//!   `__CxxFrameHandler3`. Additionally, no extra filtering is performed, so we
-//!   end up catching any C++ exceptions that happen to look like the kind we're
-//!   throwing. Note that throwing an exception into Rust is undefined behavior
-//!   anyway, so this should be fine.
+//!   `__CxxFrameHandler3`. Since we don't throw a typed C++ exception, the
+//!   catch side can't rely on `__CxxFrameHandler3`'s own RTTI matching to tell
+//!   a Rust panic apart from an unrelated exception, so the `try` intrinsic's
+//!   catch pad is a catch-all: it runs for every exception that unwinds
+//!   through it and calls `rust_eh_filter_panic` below (wired in as the
+//!   `#[lang = "msvc_try_filter"]` item, the same lang item that used to mark
+//!   the type descriptor for a typed catch) to check the exception code and a
+//!   magic sentinel before deciding the record is really one of ours. If
+//!   either check fails the exception is left to keep unwinding instead of
+//!   being caught, so a foreign C++ or SEH exception can no longer be
+//!   mistaken for a Rust panic, and `payload`/`cleanup` below are never
+//!   reached for it.
 //! * We've got some data to transmit across the unwinding boundary,
 //!   specifically a `Box<dyn Any + Send>`. Like with Dwarf exceptions
 //!   these two pointers are stored as a payload in the exception itself. On
 //!   MSVC, however, there's no need for an extra heap allocation because the
 //!   call stack is preserved while filter functions are being executed. This
-//!   means that the pointers are passed directly to `_CxxThrowException` which
+//!   means that the pointers are passed directly to `RaiseException` which
 //!   are then recovered in the filter function to be written to the stack frame
 //!   of the `try` intrinsic.
 //!
@@ -63,245 +71,123 @@ use core::mem;
 use core::raw;
 
 use windows as c;
-use libc::{c_int, c_uint};
 
-// First up, a whole bunch of type definitions. There's a few platform-specific
-// oddities here, and a lot that's just blatantly copied from LLVM. The purpose
-// of all this is to implement the `panic` function below through a call to
-// `_CxxThrowException`.
+// A "customer" (bit 29 set) SEH exception code identifying a Rust panic.
+// Picking our own code means `__CxxFrameHandler3` never confuses a Rust
+// panic for an ordinary MSVC C++ exception (and vice versa), which is the
+// whole point: a C++ exception that happens to look like the kind we used
+// to throw can no longer be caught by `catch_unwind`. 0xE0527573 spells out
+// "Rus" in the low three bytes.
+const RUST_PANIC_EXCEPTION_CODE: c::DWORD = 0xE0527573;
+
+// A fixed sentinel stored alongside the payload pointers in the exception's
+// `ExceptionInformation` array. `RUST_PANIC_EXCEPTION_CODE` alone already
+// makes collisions exceedingly unlikely, but checking this as well costs
+// nothing and means an exception only needs to match on both the code *and*
+// this word before we treat its `ExceptionInformation` as trustworthy.
 //
-// This function takes two arguments. The first is a pointer to the data we're
-// passing in, which in this case is our trait object. Pretty easy to find! The
-// next, however, is more complicated. This is a pointer to a `_ThrowInfo`
-// structure, and it generally is just intended to just describe the exception
-// being thrown.
+// `ExceptionInformation` is an array of `ULONG_PTR`, i.e. pointer-width:
+// 4 bytes on x86, 8 bytes on x86_64 and aarch64. We use `usize` rather than
+// a fixed-width integer everywhere we build or read this array so the
+// layout is correct on every arch without a separate per-arch module, which
+// also means the sentinel itself has to fit in 32 bits so it's a valid
+// value on x86 too.
 //
-// Currently the definition of this type [1] is a little hairy, and the main
-// oddity (and difference from the online article) is that on 32-bit the
-// pointers are pointers but on 64-bit the pointers are expressed as 32-bit
-// offsets from the `__ImageBase` symbol. The `ptr_t` and `ptr!` macro in the
-// modules below are used to express this.
-//
-// The maze of type definitions also closely follows what LLVM emits for this
-// sort of operation. For example, if you compile this C++ code on MSVC and emit
-// the LLVM IR:
-//
-//      #include <stdin.h>
-//
-//      void foo() {
-//          uint64_t a[2] = {0, 1};
-//          throw a;
-//      }
-//
-// That's essentially what we're trying to emulate. Most of the constant values
-// below were just copied from LLVM, I'm at least not 100% sure what's going on
-// everywhere. For example the `.PA_K\0` and `.PEA_K\0` strings below (stuck in
-// the names of a few of these) I'm not actually sure what they do, but it seems
-// to mirror what LLVM does!
-//
-// In any case, these structures are all constructed in a similar manner, and
-// it's just somewhat verbose for us.
-//
-// [1]: http://www.geoffchappell.com/studies/msvc/language/predefined/
-
-#[cfg(target_arch = "x86")]
-#[macro_use]
-mod imp {
-    pub type ptr_t = *mut u8;
-    pub const OFFSET: i32 = 4;
-
-    pub const NAME1: [u8; 7] = [b'.', b'P', b'A', b'_', b'K', 0, 0];
-    pub const NAME2: [u8; 7] = [b'.', b'P', b'A', b'X', 0, 0, 0];
-
-    macro_rules! ptr {
-        (0) => (0 as *mut u8);
-        ($e:expr) => ($e as *mut u8);
-    }
-}
-
-#[cfg(target_arch = "x86_64")]
-#[macro_use]
-mod imp {
-    pub type ptr_t = u32;
-    pub const OFFSET: i32 = 8;
-
-    pub const NAME1: [u8; 7] = [b'.', b'P', b'E', b'A', b'_', b'K', 0];
-    pub const NAME2: [u8; 7] = [b'.', b'P', b'E', b'A', b'X', 0, 0];
-
-    extern "C" {
-        pub static __ImageBase: u8;
-    }
-
-    macro_rules! ptr {
-        (0) => (0);
-        ($e:expr) => {
-            (($e as usize) - (&imp::__ImageBase as *const _ as usize)) as u32
-        }
-    }
-}
-
-#[repr(C)]
-pub struct _ThrowInfo {
-    pub attribues: c_uint,
-    pub pnfnUnwind: imp::ptr_t,
-    pub pForwardCompat: imp::ptr_t,
-    pub pCatchableTypeArray: imp::ptr_t,
-}
-
-#[repr(C)]
-pub struct _CatchableTypeArray {
-    pub nCatchableTypes: c_int,
-    pub arrayOfCatchableTypes: [imp::ptr_t; 2],
-}
-
-#[repr(C)]
-pub struct _CatchableType {
-    pub properties: c_uint,
-    pub pType: imp::ptr_t,
-    pub thisDisplacement: _PMD,
-    pub sizeOrOffset: c_int,
-    pub copy_function: imp::ptr_t,
+// This is also why aarch64-pc-windows-msvc needs no arch-specific code here
+// at all: the old `_CxxThrowException`-based design needed a dedicated
+// `imp` module per arch to describe pointers as either raw pointers (x86)
+// or 32-bit `__ImageBase`-relative offsets (x86_64/aarch64), but plain
+// `usize` already has the right width and representation for
+// `RaiseException`'s `ExceptionInformation` on all three targets.
+const RUST_PANIC_SENTINEL: usize = 0x5250414e; // "RPAN"
+
+/// Where a panic originated, captured at throw time so it survives past the
+/// point where the stack that produced it has been torn down.
+pub struct Location {
+    pub file: &'static str,
+    pub line: u32,
+    pub col: u32,
 }
 
-#[repr(C)]
-pub struct _PMD {
-    pub mdisp: c_int,
-    pub pdisp: c_int,
-    pub vdisp: c_int,
+/// Extra panic metadata carried through the unwind alongside the
+/// `Box<dyn Any + Send>` payload. Boxed separately so that the exception's
+/// `ExceptionInformation` array only has to grow by a single pointer-sized
+/// word to carry it.
+pub struct PanicContext {
+    pub backtrace: Option<Box<[usize]>>,
+    pub location: Location,
 }
 
-#[repr(C)]
-pub struct _TypeDescriptor {
-    pub pVFTable: *const u8,
-    pub spare: *mut u8,
-    pub name: [u8; 7],
+// The context stashed by the most recent `cleanup` call, for higher layers
+// (e.g. a `catch_unwind` caller that wants to report where a panic came
+// from) to pick up via `take_panic_context`. Panics on independent threads
+// unwind concurrently, so this has to be `#[thread_local]` rather than a
+// plain `static mut`: otherwise two threads calling `cleanup` around the
+// same time would race on it, which could hand thread A's context to
+// thread B's `take_panic_context`, or double-free the boxed context.
+// (The crate root enables `#![feature(thread_local)]` for this.)
+#[thread_local]
+static mut LAST_PANIC_CONTEXT: Option<Box<PanicContext>> = None;
+
+/// Returns the context captured for the most recently cleaned-up panic, if
+/// any, removing it so it's only returned once.
+pub unsafe fn take_panic_context() -> Option<Box<PanicContext>> {
+    LAST_PANIC_CONTEXT.take()
 }
 
-static mut THROW_INFO: _ThrowInfo = _ThrowInfo {
-    attribues: 0,
-    pnfnUnwind: ptr!(0),
-    pForwardCompat: ptr!(0),
-    pCatchableTypeArray: ptr!(0),
-};
-
-static mut CATCHABLE_TYPE_ARRAY: _CatchableTypeArray = _CatchableTypeArray {
-    nCatchableTypes: 2,
-    arrayOfCatchableTypes: [ptr!(0), ptr!(0)],
-};
-
-static mut CATCHABLE_TYPE1: _CatchableType = _CatchableType {
-    properties: 1,
-    pType: ptr!(0),
-    thisDisplacement: _PMD {
-        mdisp: 0,
-        pdisp: -1,
-        vdisp: 0,
-    },
-    sizeOrOffset: imp::OFFSET,
-    copy_function: ptr!(0),
-};
-
-static mut CATCHABLE_TYPE2: _CatchableType = _CatchableType {
-    properties: 1,
-    pType: ptr!(0),
-    thisDisplacement: _PMD {
-        mdisp: 0,
-        pdisp: -1,
-        vdisp: 0,
-    },
-    sizeOrOffset: imp::OFFSET,
-    copy_function: ptr!(0),
-};
-
-extern "C" {
-    // The leading `\x01` byte here is actually a magical signal to LLVM to
-    // *not* apply any other mangling like prefixing with a `_` character.
-    //
-    // This symbol is the vtable used by C++'s `std::type_info`. Objects of type
-    // `std::type_info`, type descriptors, have a pointer to this table. Type
-    // descriptors are referenced by the C++ EH structures defined above and
-    // that we construct below.
-    #[link_name = "\x01??_7type_info@@6B@"]
-    static TYPE_INFO_VTABLE: *const u8;
-}
-
-// We use #[lang = "msvc_try_filter"] here as this is the type descriptor which
-// we'll use in LLVM's `catchpad` instruction which ends up also being passed as
-// an argument to the C++ personality function.
-//
-// Again, I'm not entirely sure what this is describing, it just seems to work.
-#[cfg_attr(not(test), lang = "msvc_try_filter")]
-static mut TYPE_DESCRIPTOR1: _TypeDescriptor = _TypeDescriptor {
-    pVFTable: unsafe { &TYPE_INFO_VTABLE } as *const _ as *const _,
-    spare: 0 as *mut _,
-    name: imp::NAME1,
-};
-
-static mut TYPE_DESCRIPTOR2: _TypeDescriptor = _TypeDescriptor {
-    pVFTable: unsafe { &TYPE_INFO_VTABLE } as *const _ as *const _,
-    spare: 0 as *mut _,
-    name: imp::NAME2,
-};
-
-pub unsafe fn panic(data: Box<dyn Any + Send>) -> u32 {
-    use core::intrinsics::atomic_store;
-
-    // _CxxThrowException executes entirely on this stack frame, so there's no
+pub unsafe fn panic(data: Box<dyn Any + Send>, context: PanicContext) -> u32 {
+    // `RaiseException` executes entirely on this stack frame, so there's no
     // need to otherwise transfer `data` to the heap. We just pass a stack
     // pointer to this function.
     //
-    // The first argument is the payload being thrown (our two pointers), and
-    // the second argument is the type information object describing the
-    // exception (constructed above).
+    // `context`, on the other hand, is boxed: `cleanup` keeps it in
+    // `LAST_PANIC_CONTEXT` for `take_panic_context`, which can run after this
+    // frame has unwound, and boxing it lets it travel as a single word.
     let ptrs = mem::transmute::<_, raw::TraitObject>(data);
-    let mut ptrs = [ptrs.data as u64, ptrs.vtable as u64];
-    let mut ptrs_ptr = ptrs.as_mut_ptr();
-
-    // This... may seems surprising, and justifiably so. On 32-bit MSVC the
-    // pointers between these structure are just that, pointers. On 64-bit MSVC,
-    // however, the pointers between structures are rather expressed as 32-bit
-    // offsets from `__ImageBase`.
-    //
-    // Consequently, on 32-bit MSVC we can declare all these pointers in the
-    // `static`s above. On 64-bit MSVC, we would have to express subtraction of
-    // pointers in statics, which Rust does not currently allow, so we can't
-    // actually do that.
-    //
-    // The next best thing, then is to fill in these structures at runtime
-    // (panicking is already the "slow path" anyway). So here we reinterpret all
-    // of these pointer fields as 32-bit integers and then store the
-    // relevant value into it (atomically, as concurrent panics may be
-    // happening). Technically the runtime will probably do a nonatomic read of
-    // these fields, but in theory they never read the *wrong* value so it
-    // shouldn't be too bad...
-    //
-    // In any case, we basically need to do something like this until we can
-    // express more operations in statics (and we may never be able to).
-    atomic_store(&mut THROW_INFO.pCatchableTypeArray as *mut _ as *mut u32,
-                 ptr!(&CATCHABLE_TYPE_ARRAY as *const _) as u32);
-    atomic_store(&mut CATCHABLE_TYPE_ARRAY.arrayOfCatchableTypes[0] as *mut _ as *mut u32,
-                 ptr!(&CATCHABLE_TYPE1 as *const _) as u32);
-    atomic_store(&mut CATCHABLE_TYPE_ARRAY.arrayOfCatchableTypes[1] as *mut _ as *mut u32,
-                 ptr!(&CATCHABLE_TYPE2 as *const _) as u32);
-    atomic_store(&mut CATCHABLE_TYPE1.pType as *mut _ as *mut u32,
-                 ptr!(&TYPE_DESCRIPTOR1 as *const _) as u32);
-    atomic_store(&mut CATCHABLE_TYPE2.pType as *mut _ as *mut u32,
-                 ptr!(&TYPE_DESCRIPTOR2 as *const _) as u32);
-
-    c::_CxxThrowException(&mut ptrs_ptr as *mut _ as *mut _,
-                          &mut THROW_INFO as *mut _ as *mut _);
+    let context = Box::into_raw(Box::new(context)) as usize;
+
+    // The `ExceptionInformation` array carries our two payload pointers, the
+    // context pointer, and `RUST_PANIC_SENTINEL` last. `rust_eh_filter_panic`
+    // below checks the exception code and this sentinel before trusting the
+    // rest of the array and handing the pointers to `cleanup`.
+    let mut ptrs = [ptrs.data as usize, ptrs.vtable as usize, context, RUST_PANIC_SENTINEL];
+
+    c::RaiseException(RUST_PANIC_EXCEPTION_CODE,
+                       0,
+                       ptrs.len() as c::DWORD,
+                       ptrs.as_mut_ptr() as *const _);
     u32::max_value()
 }
 
-pub fn payload() -> [u64; 2] {
-    [0; 2]
+// We use #[lang = "msvc_try_filter"] here for the same reason the old
+// type-descriptor static did: it's the hook the `try` intrinsic's codegen
+// looks up to decide what runs in the `catchpad`. Previously that hook was
+// a `_TypeDescriptor` naming the C++ type we were (supposedly) throwing, so
+// the catch matched by RTTI -- which is exactly what let an unrelated C++
+// exception with a similar-looking type get caught as a Rust panic. Now
+// that `panic` above raises a plain SEH exception instead of a typed C++
+// one, the `try` intrinsic's `catchpad` is generated as a catch-all (a null
+// type descriptor) and this lang item instead names the filter function it
+// calls with the raw exception code and `ExceptionInformation` before
+// running the catch body, so codegen only proceeds into `payload`/`cleanup`
+// when this returns `true`.
+#[cfg_attr(not(test), lang = "msvc_try_filter")]
+#[no_mangle]
+pub unsafe extern "C" fn rust_eh_filter_panic(code: c::DWORD, info: *const usize) -> bool {
+    code == RUST_PANIC_EXCEPTION_CODE && *info.offset(3) == RUST_PANIC_SENTINEL
+}
+
+pub fn payload() -> [usize; 3] {
+    [0; 3]
 }
 
-pub unsafe fn cleanup(payload: [u64; 2]) -> Box<dyn Any + Send> {
-    mem::transmute(raw::TraitObject {
+pub unsafe fn cleanup(payload: [usize; 3]) -> Box<dyn Any + Send> {
+    let data = mem::transmute(raw::TraitObject {
         data: payload[0] as *mut _,
         vtable: payload[1] as *mut _,
-    })
+    });
+    LAST_PANIC_CONTEXT = Some(Box::from_raw(payload[2] as *mut PanicContext));
+    data
 }
 
 // This is required by the compiler to exist (e.g. it's a lang item), but